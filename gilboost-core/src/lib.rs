@@ -1,10 +1,13 @@
-use std::time::Duration;
-use tokio::sync::{mpsc::{self, Receiver, Sender}, oneshot};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Receiver, Sender};
 use futures::{stream::SelectAll, StreamExt, FutureExt};
 use async_stream::stream;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
 use tokio::task::{JoinHandle};
 use uuid::Uuid;
 
@@ -59,46 +62,364 @@ pub async fn sleep_ms(ms: u64) {
     tokio::time::sleep(Duration::from_millis(ms)).await;
 }
 
+/// A node in a tree of cancellation tokens. Cancelling a node cancels every
+/// descendant; dropping the last handle to a node prunes it from its parent
+/// so an abandoned subtree doesn't linger in memory.
+struct Node {
+    cancelled: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+    parent: Option<Weak<Node>>,
+    children: Mutex<Vec<Arc<Node>>>,
+    handles: AtomicUsize,
+}
+
+/// A handle into a tree of cancellation tokens, inspired by tokio-util's
+/// `CancellationToken`. Cancelling a token cancels it and, recursively,
+/// every child token derived from it via [`CancellationToken::child_token`].
+pub struct CancellationToken {
+    node: Arc<Node>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken {
+            node: Arc::new(Node {
+                cancelled: AtomicBool::new(false),
+                wakers: Mutex::new(Vec::new()),
+                parent: None,
+                children: Mutex::new(Vec::new()),
+                handles: AtomicUsize::new(1),
+            }),
+        }
+    }
+
+    /// Derive a child token. If this token is already cancelled, the child
+    /// observes `cancelled() == true` immediately.
+    pub fn child_token(&self) -> CancellationToken {
+        // Hold `children` while reading `cancelled` so this can't race a
+        // concurrent `cancel()`: either we see its flag flip and the child
+        // is born already cancelled, or we register before it takes its
+        // snapshot and get recursed into normally.
+        let mut children = self.node.children.lock().unwrap();
+        let child = Arc::new(Node {
+            cancelled: AtomicBool::new(self.node.cancelled.load(Ordering::SeqCst)),
+            wakers: Mutex::new(Vec::new()),
+            parent: Some(Arc::downgrade(&self.node)),
+            children: Mutex::new(Vec::new()),
+            handles: AtomicUsize::new(1),
+        });
+        children.push(child.clone());
+        CancellationToken { node: child }
+    }
+
+    pub fn cancelled_flag(&self) -> bool {
+        self.node.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Cancel this token and every descendant. Idempotent.
+    pub fn cancel(&self) {
+        // Flip the flag under the same lock `child_token()` takes, so a
+        // concurrent child registration can't slip in between our flag
+        // flip and our children snapshot.
+        let children = {
+            let children = self.node.children.lock().unwrap();
+            if self.node.cancelled.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            children.clone()
+        };
+
+        let wakers = std::mem::take(&mut *self.node.wakers.lock().unwrap());
+        for waker in wakers {
+            waker.wake();
+        }
+
+        for child in children {
+            (CancellationToken { node: child }).cancel();
+        }
+    }
+
+    /// Resolves once this token is cancelled; returns immediately if it
+    /// already is.
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled {
+            node: self.node.clone(),
+        }
+    }
+}
+
+impl Clone for CancellationToken {
+    fn clone(&self) -> Self {
+        self.node.handles.fetch_add(1, Ordering::SeqCst);
+        CancellationToken {
+            node: self.node.clone(),
+        }
+    }
+}
+
+impl Drop for CancellationToken {
+    fn drop(&mut self) {
+        if self.node.handles.fetch_sub(1, Ordering::SeqCst) != 1 {
+            return;
+        }
+        if let Some(parent) = self.node.parent.as_ref().and_then(Weak::upgrade) {
+            parent
+                .children
+                .lock()
+                .unwrap()
+                .retain(|child| !Arc::ptr_eq(child, &self.node));
+        }
+    }
+}
+
+pub struct Cancelled {
+    node: Arc<Node>,
+}
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.node.cancelled.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        self.node.wakers.lock().unwrap().push(cx.waker().clone());
+        if self.node.cancelled.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+/// Outcome of a supervised task attempt: `Ok` ends supervision, `Retry`/`Err`
+/// trigger a backed-off re-spawn (up to the governing `SupervisionPolicy`).
+#[derive(Clone, Debug)]
+pub enum TaskOutcome {
+    Ok,
+    Retry,
+    Err(String),
+}
+
+/// Governs how a supervised task is retried after a failed attempt.
+#[derive(Clone, Copy)]
+pub struct SupervisionPolicy {
+    pub max_retries: u32,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+    pub jitter: Duration,
+}
+
+impl Default for SupervisionPolicy {
+    fn default() -> Self {
+        SupervisionPolicy {
+            max_retries: 0,
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            jitter: Duration::from_millis(0),
+        }
+    }
+}
+
+/// Lifecycle of a task under supervision, queryable via `TaskManager::state`.
+#[derive(Clone, Debug)]
+pub enum TaskState {
+    Running,
+    Restarting { attempt: u32, next_at: Instant },
+    Failed,
+    Cancelled,
+}
+
+fn jittered(max_jitter: Duration) -> Duration {
+    if max_jitter.is_zero() {
+        return Duration::ZERO;
+    }
+    let bound = max_jitter.as_nanos().max(1) as u64;
+    let seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    Duration::from_nanos(seed % bound)
+}
+
+struct TaskEntry {
+    token: CancellationToken,
+    state: Arc<Mutex<TaskState>>,
+    parent: Option<CancellationToken>,
+    #[allow(dead_code)]
+    handle: JoinHandle<()>,
+}
+
+/// What's left of a task once it reaches a terminal state and is reaped out
+/// of `TaskManager::tasks`: just enough for `state()` to keep answering and
+/// for `restart()` to still find the original parent token.
+struct CompletedEntry {
+    state: TaskState,
+    parent: Option<CancellationToken>,
+}
+
+/// Removes a terminal task from the live `tasks` map and files it under
+/// `completed` instead, so `TaskManager::tasks` doesn't grow without bound
+/// for long-lived managers.
+fn reap(
+    tasks: &Arc<Mutex<HashMap<Uuid, TaskEntry>>>,
+    completed: &Arc<Mutex<HashMap<Uuid, CompletedEntry>>>,
+    id: Uuid,
+    state: &Arc<Mutex<TaskState>>,
+    parent: &Option<CancellationToken>,
+) {
+    tasks.lock().unwrap().remove(&id);
+    completed.lock().unwrap().insert(id, CompletedEntry {
+        state: state.lock().unwrap().clone(),
+        parent: parent.clone(),
+    });
+}
+
 pub struct TaskManager {
-    tasks: Arc<Mutex<HashMap<Uuid, (JoinHandle<()>, oneshot::Sender<()>)>>>,
+    tasks: Arc<Mutex<HashMap<Uuid, TaskEntry>>>,
+    completed: Arc<Mutex<HashMap<Uuid, CompletedEntry>>>,
 }
 
 impl TaskManager {
     pub fn new() -> Self {
         TaskManager {
             tasks: Arc::new(Mutex::new(HashMap::new())),
+            completed: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    pub fn spawn<F>(&self, fut: F) -> Uuid
+    /// Spawn a supervised task, optionally as a child of `parent`'s
+    /// cancellation token. `make_fut` is called again for each retry, so it
+    /// must be a factory rather than a one-shot future. A panic in an
+    /// attempt is treated the same as `TaskOutcome::Err`. Returns the task
+    /// id and the token governing it; cancelling that token (or any
+    /// ancestor) cancels this task and aborts any pending backoff.
+    pub fn spawn<F, Fut>(
+        &self,
+        make_fut: F,
+        parent: Option<&CancellationToken>,
+        policy: SupervisionPolicy,
+    ) -> (Uuid, CancellationToken)
     where
-        F: Future<Output = ()> + Send + 'static,
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = TaskOutcome> + Send + 'static,
     {
-        let (tx, rx) = oneshot::channel();
-        let cancelable = async move {
-            tokio::select! {
-                _ = fut => {},
-                _ = rx => {},
+        let token = match parent {
+            Some(parent) => parent.child_token(),
+            None => CancellationToken::new(),
+        };
+        let parent_for_entry = parent.cloned();
+        let id = Uuid::new_v4();
+        let state = Arc::new(Mutex::new(TaskState::Running));
+
+        let run_token = token.clone();
+        let run_state = state.clone();
+        let tasks_for_reap = self.tasks.clone();
+        let completed_for_reap = self.completed.clone();
+        let parent_for_reap = parent_for_entry.clone();
+        let supervised = async move {
+            let mut attempt: u32 = 0;
+            loop {
+                *run_state.lock().unwrap() = TaskState::Running;
+
+                let attempt_handle = tokio::spawn(make_fut());
+                let abort_handle = attempt_handle.abort_handle();
+                let outcome = tokio::select! {
+                    res = attempt_handle => Some(res),
+                    _ = run_token.cancelled() => {
+                        abort_handle.abort();
+                        None
+                    }
+                };
+
+                let retryable = match outcome {
+                    None => {
+                        *run_state.lock().unwrap() = TaskState::Cancelled;
+                        reap(&tasks_for_reap, &completed_for_reap, id, &run_state, &parent_for_reap);
+                        return;
+                    }
+                    Some(Ok(TaskOutcome::Ok)) => {
+                        reap(&tasks_for_reap, &completed_for_reap, id, &run_state, &parent_for_reap);
+                        return;
+                    }
+                    Some(Ok(TaskOutcome::Retry)) | Some(Ok(TaskOutcome::Err(_))) | Some(Err(_)) => true,
+                };
+
+                if !retryable || attempt >= policy.max_retries {
+                    *run_state.lock().unwrap() = TaskState::Failed;
+                    reap(&tasks_for_reap, &completed_for_reap, id, &run_state, &parent_for_reap);
+                    return;
+                }
+
+                let capped_attempt = attempt.min(31);
+                let backoff = policy
+                    .base_backoff
+                    .checked_mul(1u32 << capped_attempt)
+                    .unwrap_or(policy.max_backoff)
+                    .min(policy.max_backoff);
+                let delay = backoff + jittered(policy.jitter);
+                let next_at = Instant::now() + delay;
+                *run_state.lock().unwrap() = TaskState::Restarting {
+                    attempt: attempt + 1,
+                    next_at,
+                };
+
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {},
+                    _ = run_token.cancelled() => {
+                        *run_state.lock().unwrap() = TaskState::Cancelled;
+                        reap(&tasks_for_reap, &completed_for_reap, id, &run_state, &parent_for_reap);
+                        return;
+                    }
+                }
+
+                attempt += 1;
             }
         };
 
-        let handle = tokio::spawn(cancelable);
-        let id = Uuid::new_v4();
-        self.tasks.lock().unwrap().insert(id, (handle, tx));
-        id
+        // Hold `tasks` across the spawn so a task that finishes at once can't
+        // be reaped before its entry is inserted and then linger in `tasks`.
+        let mut tasks = self.tasks.lock().unwrap();
+        let handle = tokio::spawn(supervised);
+        tasks.insert(id, TaskEntry { handle, token: token.clone(), state, parent: parent_for_entry });
+        (id, token)
     }
 
+    /// Current lifecycle state of a task, or `None` if it was never spawned.
+    /// Terminal states are served from the `completed` side table once the
+    /// task has been reaped out of `tasks`.
+    pub fn state(&self, id: &Uuid) -> Option<TaskState> {
+        if let Some(entry) = self.tasks.lock().unwrap().get(id) {
+            return Some(entry.state.lock().unwrap().clone());
+        }
+        self.completed.lock().unwrap().get(id).map(|entry| entry.state.clone())
+    }
+
+    /// Cancels the task and aborts any pending backoff timer it's waiting on.
     pub fn cancel(&self, id: &Uuid) {
-        if let Some((_, tx)) = self.tasks.lock().unwrap().remove(id) {
-            let _ = tx.send(());
+        if let Some(entry) = self.tasks.lock().unwrap().get(id) {
+            entry.token.cancel();
         }
     }
 
-    pub fn restart<F>(&self, id: &Uuid, new_fut: F) -> Uuid
+    /// Re-spawns the task under the same parent token it originally had
+    /// (looked up from the live entry, or the completed side table if it
+    /// already reached a terminal state), so a restarted task stays in the
+    /// same cancellation subtree instead of escaping to the root.
+    pub fn restart<F, Fut>(&self, id: &Uuid, make_fut: F, policy: SupervisionPolicy) -> Uuid
     where
-        F: Future<Output = ()> + Send + 'static,
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = TaskOutcome> + Send + 'static,
     {
+        let parent = self
+            .tasks
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|entry| entry.parent.clone())
+            .or_else(|| self.completed.lock().unwrap().get(id).map(|entry| entry.parent.clone()))
+            .flatten();
+
         self.cancel(id);
-        self.spawn(new_fut)
+        self.spawn(make_fut, parent.as_ref(), policy).0
     }
 }
@@ -1,14 +1,11 @@
 use std::time::Duration;
-use tokio::sync::{mpsc::{self, Receiver, Sender}, oneshot};
-use futures::{stream::SelectAll, StreamExt, FutureExt};
+use futures::stream::SelectAll;
 use async_stream::stream;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::future::Future;
-use tokio::task::JoinHandle;
+use std::sync::Arc;
 use uuid::Uuid;
 use pyo3::prelude::*;
-use pyo3::types::PyString;
+use pyo3::types::PyBytes;
+use gilboost_core::{CancellationToken, Channel};
 
 #[pyclass]
 #[derive(Clone)]
@@ -43,6 +40,128 @@ impl PyChannel {
             }
         })
     }
+
+    /// Like `recv`, but also races against `handle`'s cancellation token so
+    /// cancelling a task group interrupts a pending receive.
+    pub fn recv_cancellable<'a>(&self, py: Python<'a>, handle: PyTaskHandle) -> PyResult<&'a PyAny> {
+        let inner = self.inner.clone();
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            tokio::select! {
+                val = inner.recv() => Ok(match val {
+                    Some(v) => v,
+                    None => "<closed>".to_string(),
+                }),
+                _ = handle.token.cancelled() => {
+                    Err(pyo3::exceptions::PyRuntimeError::new_err("channel receive cancelled"))
+                }
+            }
+        })
+    }
+}
+
+/// A Python handle onto a node in a tree of cancellation tokens. Cancelling
+/// a handle cancels every handle derived from it via `child()`.
+#[pyclass]
+pub struct PyTaskHandle {
+    id: Uuid,
+    token: CancellationToken,
+}
+
+#[pymethods]
+impl PyTaskHandle {
+    #[new]
+    pub fn new() -> Self {
+        PyTaskHandle {
+            id: Uuid::new_v4(),
+            token: CancellationToken::new(),
+        }
+    }
+
+    pub fn child(&self) -> PyTaskHandle {
+        PyTaskHandle {
+            id: Uuid::new_v4(),
+            token: self.token.child_token(),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.cancelled_flag()
+    }
+
+    #[getter]
+    pub fn id(&self) -> String {
+        self.id.to_string()
+    }
+}
+
+impl Clone for PyTaskHandle {
+    fn clone(&self) -> Self {
+        PyTaskHandle {
+            id: self.id,
+            token: self.token.clone(),
+        }
+    }
+}
+
+/// Python wrapper around `FrameChannel`, a length-delimited framing layer for
+/// streaming binary payloads without hand-rolled chunking/reassembly on the
+/// Python side.
+#[pyclass]
+#[derive(Clone)]
+pub struct PyFrameChannel {
+    inner: FrameChannel,
+}
+
+#[pymethods]
+impl PyFrameChannel {
+    #[new]
+    pub fn new_py(cap: usize, max_frame_len: Option<usize>, prefix_bytes: Option<u8>) -> PyResult<Self> {
+        let max_frame_len = max_frame_len.unwrap_or(16 * 1024 * 1024);
+        let prefix_width = match prefix_bytes.unwrap_or(4) {
+            2 => PrefixWidth::U16,
+            4 => PrefixWidth::U32,
+            8 => PrefixWidth::U64,
+            other => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "prefix_bytes must be 2, 4, or 8 (got {})",
+                    other
+                )))
+            }
+        };
+        Ok(PyFrameChannel {
+            inner: FrameChannel::new(cap, max_frame_len, prefix_width)
+                .map_err(pyo3::exceptions::PyValueError::new_err)?,
+        })
+    }
+
+    pub fn send_frame<'a>(&self, py: Python<'a>, payload: &[u8]) -> PyResult<&'a PyAny> {
+        let inner = self.inner.clone();
+        let payload = payload.to_vec();
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            inner
+                .send_frame(payload)
+                .await
+                .map_err(pyo3::exceptions::PyValueError::new_err)
+        })
+    }
+
+    pub fn recv_frame<'a>(&self, py: Python<'a>) -> PyResult<&'a PyAny> {
+        let inner = self.inner.clone();
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            let frame = inner
+                .recv_frame()
+                .await
+                .map_err(pyo3::exceptions::PyValueError::new_err)?;
+            Python::with_gil(|py| match frame {
+                Some(bytes) => Ok(PyBytes::new(py, &bytes).into_py(py)),
+                None => Ok(py.None()),
+            })
+        })
+    }
 }
 
 #[pyfunction]
@@ -56,97 +175,152 @@ fn sleep(py: Python, ms: u64) -> PyResult<&PyAny> {
 #[pymodule]
 fn gilboost_core(py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyChannel>()?;
+    m.add_class::<PyFrameChannel>()?;
+    m.add_class::<PyTaskHandle>()?;
     m.add_function(wrap_pyfunction!(sleep, m)?)?;
     Ok(())
 }
 
-#[derive(Clone)]
-pub struct Channel<T: Send + 'static> {
-    sender: Sender<T>,
-    receiver: Arc<tokio::sync::Mutex<Receiver<T>>>,
+/// Width of the length prefix written ahead of each frame.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PrefixWidth {
+    U16,
+    U32,
+    U64,
 }
 
-impl<T: Send + 'static> Channel<T> {
-    pub fn new(capacity: usize) -> Self {
-        let (sender, receiver) = mpsc::channel(capacity);
-        Channel {
-            sender,
-            receiver: Arc::new(tokio::sync::Mutex::new(receiver)),
+impl PrefixWidth {
+    fn byte_len(&self) -> usize {
+        match self {
+            PrefixWidth::U16 => 2,
+            PrefixWidth::U32 => 4,
+            PrefixWidth::U64 => 8,
         }
     }
 
-    pub async fn send(&self, value: T) {
-        let _ = self.sender.send(value).await;
+    /// The largest frame length this prefix width can encode without
+    /// truncation.
+    fn max_representable_len(&self) -> usize {
+        match self {
+            PrefixWidth::U16 => u16::MAX as usize,
+            PrefixWidth::U32 => u32::MAX as usize,
+            PrefixWidth::U64 => usize::MAX,
+        }
     }
 
-    pub async fn recv(&self) -> Option<T> {
-        let mut receiver = self.receiver.lock().await;
-        receiver.recv().await
+    fn encode(&self, len: usize, out: &mut Vec<u8>) {
+        match self {
+            PrefixWidth::U16 => out.extend_from_slice(&(len as u16).to_be_bytes()),
+            PrefixWidth::U32 => out.extend_from_slice(&(len as u32).to_be_bytes()),
+            PrefixWidth::U64 => out.extend_from_slice(&(len as u64).to_be_bytes()),
+        }
     }
 
-    pub fn into_stream(self) -> impl futures::Stream<Item = T> {
-        let (sender, mut receiver) = (self.sender.clone(), self.receiver);
-        stream! {
-            loop {
-                let mut lock = receiver.lock().await;
-                if let Some(item) = lock.recv().await {
-                    yield item;
-                } else {
-                    break;
-                }
-            }
+    fn decode(&self, bytes: &[u8]) -> usize {
+        match self {
+            PrefixWidth::U16 => u16::from_be_bytes(bytes.try_into().unwrap()) as usize,
+            PrefixWidth::U32 => u32::from_be_bytes(bytes.try_into().unwrap()) as usize,
+            PrefixWidth::U64 => u64::from_be_bytes(bytes.try_into().unwrap()) as usize,
         }
     }
 }
 
-pub async fn select_channels<T: Send + Unpin + 'static>(channels: Vec<Channel<T>>) -> impl futures::Stream<Item = T> {
-    let mut streams = SelectAll::new();
-    for ch in channels.into_iter() {
-        streams.push(Box::pin(ch.into_stream()));
-    }
-    streams
+/// A length-delimited framing layer over `Channel<Vec<u8>>`, modeled on
+/// tokio-util's length-delimited codec: each frame is written as a
+/// fixed-width big-endian length prefix followed by its payload, and the
+/// read side buffers partial chunks until a full frame is available.
+#[derive(Clone)]
+pub struct FrameChannel {
+    inner: Channel<Vec<u8>>,
+    buffer: Arc<tokio::sync::Mutex<Vec<u8>>>,
+    max_frame_len: usize,
+    prefix_width: PrefixWidth,
 }
 
-pub struct TaskManager {
-    tasks: Arc<Mutex<HashMap<Uuid, (JoinHandle<()>, oneshot::Sender<()>)>>>,
-}
+impl FrameChannel {
+    /// Fails if `max_frame_len` can't be represented in `prefix_width` bytes,
+    /// since `encode` would otherwise wrap around silently and corrupt the
+    /// frame stream instead of rejecting the oversized frame.
+    pub fn new(capacity: usize, max_frame_len: usize, prefix_width: PrefixWidth) -> Result<Self, String> {
+        if max_frame_len > prefix_width.max_representable_len() {
+            return Err(format!(
+                "max_frame_len of {} bytes cannot be represented by a {}-byte prefix (max {} bytes)",
+                max_frame_len,
+                prefix_width.byte_len(),
+                prefix_width.max_representable_len()
+            ));
+        }
+        Ok(FrameChannel {
+            inner: Channel::new(capacity),
+            buffer: Arc::new(tokio::sync::Mutex::new(Vec::new())),
+            max_frame_len,
+            prefix_width,
+        })
+    }
 
-impl TaskManager {
-    pub fn new() -> Self {
-        TaskManager {
-            tasks: Arc::new(Mutex::new(HashMap::new())),
+    pub async fn send_frame(&self, payload: Vec<u8>) -> Result<(), String> {
+        if payload.len() > self.max_frame_len {
+            return Err(format!(
+                "frame of {} bytes exceeds max_frame_len of {} bytes",
+                payload.len(),
+                self.max_frame_len
+            ));
         }
+        let mut framed = Vec::with_capacity(self.prefix_width.byte_len() + payload.len());
+        self.prefix_width.encode(payload.len(), &mut framed);
+        framed.extend_from_slice(&payload);
+        self.inner.send(framed).await;
+        Ok(())
     }
 
-    pub fn spawn<F>(&self, fut: F) -> Uuid
-    where
-        F: Future<Output = ()> + Send + 'static,
-    {
-        let (tx, rx) = oneshot::channel();
-        let cancelable = async move {
-            tokio::select! {
-                _ = fut => {},
-                _ = rx => {},
+    /// Returns the next complete frame, buffering partial reads as needed.
+    /// Resolves to `None` once the underlying channel is closed with no
+    /// further frames available.
+    pub async fn recv_frame(&self) -> Result<Option<Vec<u8>>, String> {
+        let header_len = self.prefix_width.byte_len();
+        let mut buffer = self.buffer.lock().await;
+
+        loop {
+            if buffer.len() >= header_len {
+                let frame_len = self.prefix_width.decode(&buffer[..header_len]);
+                if frame_len > self.max_frame_len {
+                    return Err(format!(
+                        "frame header declares {} bytes, exceeding max_frame_len of {} bytes",
+                        frame_len, self.max_frame_len
+                    ));
+                }
+                if buffer.len() >= header_len + frame_len {
+                    let frame = buffer[header_len..header_len + frame_len].to_vec();
+                    buffer.drain(..header_len + frame_len);
+                    return Ok(Some(frame));
+                }
             }
-        };
 
-        let handle = tokio::spawn(cancelable);
-        let id = Uuid::new_v4();
-        self.tasks.lock().unwrap().insert(id, (handle, tx));
-        id
+            match self.inner.recv().await {
+                Some(chunk) => buffer.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
     }
 
-    pub fn cancel(&self, id: &Uuid) {
-        if let Some((_, tx)) = self.tasks.lock().unwrap().remove(id) {
-            let _ = tx.send(());
+    pub fn into_frame_stream(self) -> impl futures::Stream<Item = Vec<u8>> {
+        stream! {
+            loop {
+                match self.recv_frame().await {
+                    Ok(Some(frame)) => yield frame,
+                    _ => break,
+                }
+            }
         }
     }
+}
 
-    pub fn restart<F>(&self, id: &Uuid, new_fut: F) -> Uuid
-    where
-        F: Future<Output = ()> + Send + 'static,
-    {
-        self.cancel(id);
-        self.spawn(new_fut)
+/// Merges several framed channels into one stream of decoded frames, the
+/// `FrameChannel` counterpart to `gilboost_core::select_channels`.
+pub async fn select_frame_channels(channels: Vec<FrameChannel>) -> impl futures::Stream<Item = Vec<u8>> {
+    let mut streams = SelectAll::new();
+    for ch in channels.into_iter() {
+        streams.push(Box::pin(ch.into_frame_stream()));
     }
+    streams
 }
@@ -2,8 +2,10 @@ use pyo3::prelude::*;
 use pyo3::types::{PyBytes, PyDict};
 use pyo3::wrap_pyfunction;
 use tokio::runtime::{Builder, Runtime};
+use tokio::sync::oneshot;
 use once_cell::sync::OnceCell;
 use std::sync::{Mutex, Arc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use crossbeam_channel::{bounded, Sender, Receiver};
 use std::thread;
 use std::collections::HashMap;
@@ -18,6 +20,8 @@ struct Config {
     queue_capacity: usize,
     result_ttl: Duration,
     memory_limit_mb: usize,
+    throttling_ms: u64,
+    batch_size: usize,
 }
 
 impl Default for Config {
@@ -27,6 +31,8 @@ impl Default for Config {
             queue_capacity: 1000,
             result_ttl: Duration::from_secs(3600), // 1 час
             memory_limit_mb: 1024, // 1 ГБ
+            throttling_ms: 0,
+            batch_size: 16,
         }
     }
 }
@@ -36,20 +42,48 @@ struct Task {
     data: Vec<u8>,
     priority: i32,
     created_at: Instant,
+    handler: Option<String>,
 }
 
+#[derive(Clone)]
 struct TaskResult {
     data: Vec<u8>,
+    error: Option<String>,
     created_at: Instant,
 }
 
+// All of the state a live runtime needs, bundled behind a single slot so it
+// can be torn down by `shutdown` and rebuilt by a later `init_runtime` call.
+// Plain `OnceCell`s (as used pre-shutdown) can only ever be set once, which
+// made the runtime a one-shot resource; a `Mutex<Option<_>>` lets us take it
+// out cleanly on shutdown and put a fresh one back in on reinit.
+struct RuntimeState {
+    runtime: Runtime,
+    config: Config,
+    task_queue: Arc<Mutex<PriorityQueue<Uuid, i32>>>,
+    task_data: Arc<Mutex<HashMap<Uuid, Task>>>,
+    results: Arc<Mutex<HashMap<Uuid, TaskResult>>>,
+    worker_sender: Arc<Sender<()>>,
+    pending_senders: Arc<Mutex<HashMap<Uuid, oneshot::Sender<TaskResult>>>>,
+    pending_receivers: Arc<Mutex<HashMap<Uuid, oneshot::Receiver<TaskResult>>>>,
+    stop_flag: Arc<AtomicBool>,
+    drain_flag: Arc<AtomicBool>,
+    tasks_completed: Arc<AtomicUsize>,
+    worker_handles: Vec<thread::JoinHandle<()>>,
+}
+
 // Глобальные переменные
-static RUNTIME: OnceCell<Mutex<Runtime>> = OnceCell::new();
-static CONFIG: OnceCell<Mutex<Config>> = OnceCell::new();
-static TASK_QUEUE: OnceCell<Arc<Mutex<PriorityQueue<Uuid, i32>>>> = OnceCell::new();
-static TASK_DATA: OnceCell<Arc<Mutex<HashMap<Uuid, Task>>>> = OnceCell::new();
-static RESULTS: OnceCell<Arc<Mutex<HashMap<Uuid, TaskResult>>>> = OnceCell::new();
-static WORKER_SENDER: OnceCell<Arc<Sender<()>>> = OnceCell::new();
+static STATE: Mutex<Option<RuntimeState>> = Mutex::new(None);
+// Accumulates every worker's per-tick batch size and is drained each time
+// `get_stats` reads it, so the value it reports is "tasks processed across
+// all workers since stats were last read" rather than a single worker's
+// last write -- with worker_threads > 1, `.store()`-per-worker made this
+// whichever worker happened to finish its tick last.
+static TASKS_PROCESSED_PER_TICK: AtomicUsize = AtomicUsize::new(0);
+// Handlers are registered independently of the runtime's lifecycle so they
+// survive a `shutdown`/`init_runtime` cycle instead of needing to be
+// re-registered after every restart.
+static HANDLERS: OnceCell<Mutex<HashMap<String, Py<PyAny>>>> = OnceCell::new();
 
 #[pyfunction]
 fn init_runtime(
@@ -58,9 +92,12 @@ fn init_runtime(
     queue_capacity: Option<usize>,
     result_ttl_seconds: Option<u64>,
     memory_limit_mb: Option<usize>,
+    throttling_ms: Option<u64>,
+    batch_size: Option<usize>,
 ) -> PyResult<()> {
     py.allow_threads(|| {
-        if RUNTIME.get().is_some() {
+        let mut state_guard = STATE.lock().unwrap();
+        if state_guard.is_some() {
             return Ok(());
         }
 
@@ -77,10 +114,12 @@ fn init_runtime(
         if let Some(mem) = memory_limit_mb {
             config.memory_limit_mb = mem;
         }
-
-        CONFIG.set(Mutex::new(config.clone())).map_err(|_| {
-            pyo3::exceptions::PyRuntimeError::new_err("Failed to initialize config")
-        })?;
+        if let Some(throttle) = throttling_ms {
+            config.throttling_ms = throttle;
+        }
+        if let Some(batch) = batch_size {
+            config.batch_size = batch.max(1);
+        }
 
         let rt = Builder::new_multi_thread()
             .worker_threads(config.worker_threads)
@@ -90,70 +129,143 @@ fn init_runtime(
                 pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to create runtime: {}", e))
             })?;
 
-        RUNTIME.set(Mutex::new(rt)).map_err(|_| {
-            pyo3::exceptions::PyRuntimeError::new_err("Failed to initialize runtime")
-        })?;
-
-        TASK_QUEUE.set(Arc::new(Mutex::new(PriorityQueue::new()))).map_err(|_| {
-            pyo3::exceptions::PyRuntimeError::new_err("Failed to initialize task queue")
-        })?;
-
-        TASK_DATA.set(Arc::new(Mutex::new(HashMap::new()))).map_err(|_| {
-            pyo3::exceptions::PyRuntimeError::new_err("Failed to initialize task data")
-        })?;
-
-        RESULTS.set(Arc::new(Mutex::new(HashMap::new()))).map_err(|_| {
-            pyo3::exceptions::PyRuntimeError::new_err("Failed to initialize results")
-        })?;
+        let task_queue = Arc::new(Mutex::new(PriorityQueue::new()));
+        let task_data = Arc::new(Mutex::new(HashMap::new()));
+        let results = Arc::new(Mutex::new(HashMap::new()));
+        let pending_senders = Arc::new(Mutex::new(HashMap::new()));
+        let pending_receivers = Arc::new(Mutex::new(HashMap::new()));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let drain_flag = Arc::new(AtomicBool::new(true));
+        let tasks_completed = Arc::new(AtomicUsize::new(0));
 
         let (tx, rx) = bounded::<()>(1);
-        WORKER_SENDER.set(Arc::new(tx)).map_err(|_| {
-            pyo3::exceptions::PyRuntimeError::new_err("Failed to initialize worker channel")
-        })?;
-
-        let task_queue = TASK_QUEUE.get().unwrap().clone();
-        let task_data = TASK_DATA.get().unwrap().clone();
-        let results = RESULTS.get().unwrap().clone();
-
-        thread::spawn(move || {
-            for _ in 0..config.worker_threads {
-                let task_queue = task_queue.clone();
-                let task_data = task_data.clone();
-                let results = results.clone();
-                let rx = rx.clone();
-
-                thread::spawn(move || {
-                    loop {
-                        let _ = rx.recv_timeout(Duration::from_secs(1));
-
-                        let task_option = {
-                            let mut queue = task_queue.lock().unwrap();
-                            queue.pop()
-                        };
-
-                        if let Some((task_id, _priority)) = task_option {
-                            let task = {
-                                let mut tasks = task_data.lock().unwrap();
-                                tasks.remove(&task_id)
-                            };
-
-                            if let Some(task) = task {
+        let worker_sender = Arc::new(tx);
+
+        let mut worker_handles = Vec::with_capacity(config.worker_threads);
+        for _ in 0..config.worker_threads {
+            let task_queue = task_queue.clone();
+            let task_data = task_data.clone();
+            let results = results.clone();
+            let pending_senders = pending_senders.clone();
+            let pending_receivers = pending_receivers.clone();
+            let stop_flag = stop_flag.clone();
+            let drain_flag = drain_flag.clone();
+            let tasks_completed = tasks_completed.clone();
+            let rx = rx.clone();
+            let config = config.clone();
+
+            worker_handles.push(thread::spawn(move || {
+                let throttle = Duration::from_millis(config.throttling_ms);
+                let mut next_tick = Instant::now();
+
+                loop {
+                    let stopping = stop_flag.load(Ordering::SeqCst);
+                    if stopping && !drain_flag.load(Ordering::SeqCst) {
+                        break;
+                    }
 
-                                let result = process_task(&task.data);
+                    if !stopping {
+                        if config.throttling_ms == 0 {
+                            // No throttling: wake immediately on submission,
+                            // falling back to a 1s poll.
+                            let _ = rx.recv_timeout(Duration::from_secs(1));
+                        } else {
+                            let now = Instant::now();
+                            let wait = next_tick.saturating_duration_since(now);
+                            // A submission may ping us before the tick is
+                            // due; go back to sleep for what remains so
+                            // bursts don't collapse the interval to zero.
+                            let _ = rx.recv_timeout(wait);
+                            if Instant::now() < next_tick {
+                                continue;
+                            }
+                            next_tick = Instant::now() + throttle;
+                        }
+                    }
 
-                                // Сохраняем результат
-                                let mut results_map = results.lock().unwrap();
-                                results_map.insert(task.id, TaskResult {
-                                    data: result,
-                                    created_at: Instant::now(),
-                                });
+                    // Drain up to `batch_size` tasks under a single lock
+                    // acquisition instead of locking per task.
+                    let batch: Vec<Uuid> = {
+                        let mut queue = task_queue.lock().unwrap();
+                        let mut ids = Vec::with_capacity(config.batch_size);
+                        for _ in 0..config.batch_size {
+                            match queue.pop() {
+                                Some((task_id, _priority)) => ids.push(task_id),
+                                None => break,
                             }
                         }
+                        ids
+                    };
 
+                    if batch.is_empty() {
                         cleanup_old_results(&results, config.result_ttl);
+                        if stopping {
+                            // Draining and nothing left to drain.
+                            break;
+                        }
+                        continue;
                     }
-                });
-            }
+
+                    let tasks: Vec<Task> = {
+                        let mut task_map = task_data.lock().unwrap();
+                        batch
+                            .into_iter()
+                            .filter_map(|task_id| task_map.remove(&task_id))
+                            .collect()
+                    };
+
+                    let processed: Vec<(Uuid, Vec<u8>, Option<String>)> = tasks
+                        .iter()
+                        .map(|task| {
+                            let (data, error) = process_task_with_handler(task);
+                            (task.id, data, error)
+                        })
+                        .collect();
+
+                    tasks_completed.fetch_add(processed.len(), Ordering::SeqCst);
+                    TASKS_PROCESSED_PER_TICK.fetch_add(processed.len(), Ordering::Relaxed);
+
+                    if !processed.is_empty() {
+                        let mut results_map = results.lock().unwrap();
+                        let mut senders = pending_senders.lock().unwrap();
+                        let mut receivers = pending_receivers.lock().unwrap();
+                        for (id, data, error) in processed {
+                            let result = TaskResult {
+                                data,
+                                error,
+                                created_at: Instant::now(),
+                            };
+                            if let Some(sender) = senders.remove(&id) {
+                                let _ = sender.send(result.clone());
+                            }
+                            // Whether or not anything was awaiting it, the
+                            // result has landed — drop the receiver so it
+                            // doesn't linger in the map for the lifetime of
+                            // the runtime when no one ever calls
+                            // `await_result` for this task.
+                            receivers.remove(&id);
+                            results_map.insert(id, result);
+                        }
+                    }
+
+                    cleanup_old_results(&results, config.result_ttl);
+                }
+            }));
+        }
+
+        *state_guard = Some(RuntimeState {
+            runtime: rt,
+            config,
+            task_queue,
+            task_data,
+            results,
+            worker_sender,
+            pending_senders,
+            pending_receivers,
+            stop_flag,
+            drain_flag,
+            tasks_completed,
+            worker_handles,
         });
 
         Ok(())
@@ -179,19 +291,128 @@ fn process_task(data: &[u8]) -> Vec<u8> {
     }
 }
 
+// Runs a task through its registered handler, if any, falling back to the
+// default `process_task` transform. Returns the result bytes plus an error
+// message, if the handler raised or produced something we couldn't encode.
+fn process_task_with_handler(task: &Task) -> (Vec<u8>, Option<String>) {
+    let handler_name = match &task.handler {
+        Some(name) => name,
+        None => return (process_task(&task.data), None),
+    };
+
+    let handlers = HANDLERS.get_or_init(|| Mutex::new(HashMap::new()));
+    let callable = handlers.lock().unwrap().get(handler_name).map(|h| h.clone());
+
+    let callable = match callable {
+        Some(callable) => callable,
+        None => return (Vec::new(), Some(format!("No handler registered with name '{}'", handler_name))),
+    };
+
+    Python::with_gil(|py| {
+        let payload = PyBytes::new(py, &task.data);
+        match callable.call1(py, (payload,)) {
+            Ok(result) => match encode_handler_result(py, result) {
+                Ok(bytes) => (bytes, None),
+                Err(e) => (Vec::new(), Some(e.to_string())),
+            },
+            Err(e) => {
+                let message = e
+                    .value(py)
+                    .str()
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|_| "handler raised an exception".to_string());
+                (Vec::new(), Some(message))
+            }
+        }
+    })
+}
+
+fn encode_handler_result(py: Python, result: PyObject) -> PyResult<Vec<u8>> {
+    if let Ok(bytes) = result.extract::<&PyBytes>(py) {
+        return Ok(bytes.as_bytes().to_vec());
+    }
+
+    if let Ok(dict) = result.extract::<&PyDict>(py) {
+        let json_value = pyobject_to_json(py, dict.as_ref())?;
+        return serde_json::to_vec(&json_value)
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("JSON serialization error: {}", e)));
+    }
+
+    Err(pyo3::exceptions::PyTypeError::new_err("Handler must return bytes or dict"))
+}
+
+// Walks a Python object into a `serde_json::Value`. `dict.str()` (Python's
+// `repr()`-style formatting) uses single quotes and `True`/`False`/`None`,
+// which `serde_json::from_str` rejects as invalid JSON for virtually every
+// real dict, so we build the `Value` directly from the object graph instead
+// of round-tripping through a string.
+fn pyobject_to_json(py: Python, obj: &PyAny) -> PyResult<Value> {
+    if obj.is_none() {
+        return Ok(Value::Null);
+    }
+    if let Ok(b) = obj.extract::<bool>() {
+        return Ok(Value::Bool(b));
+    }
+    if let Ok(i) = obj.extract::<i64>() {
+        return Ok(Value::Number(i.into()));
+    }
+    if let Ok(f) = obj.extract::<f64>() {
+        return serde_json::Number::from_f64(f)
+            .map(Value::Number)
+            .ok_or_else(|| pyo3::exceptions::PyValueError::new_err("cannot encode non-finite float as JSON"));
+    }
+    if let Ok(s) = obj.extract::<String>() {
+        return Ok(Value::String(s));
+    }
+    if let Ok(list) = obj.downcast::<pyo3::types::PyList>() {
+        return list
+            .iter()
+            .map(|item| pyobject_to_json(py, item))
+            .collect::<PyResult<Vec<_>>>()
+            .map(Value::Array);
+    }
+    if let Ok(dict) = obj.downcast::<PyDict>() {
+        let mut map = serde_json::Map::with_capacity(dict.len());
+        for (key, value) in dict.iter() {
+            let key = key.extract::<String>().map_err(|_| {
+                pyo3::exceptions::PyTypeError::new_err("dict keys must be strings to encode as JSON")
+            })?;
+            map.insert(key, pyobject_to_json(py, value)?);
+        }
+        return Ok(Value::Object(map));
+    }
+
+    Err(pyo3::exceptions::PyTypeError::new_err(format!(
+        "cannot encode value of type '{}' as JSON",
+        obj.get_type().name()?
+    )))
+}
+
 #[pyfunction]
-fn submit_task(py: Python, data: &PyAny, priority: Option<i32>) -> PyResult<String> {
-    if RUNTIME.get().is_none() {
-        return Err(pyo3::exceptions::PyRuntimeError::new_err(
+fn register_handler(name: String, handler: PyObject) -> PyResult<()> {
+    let handlers = HANDLERS.get_or_init(|| Mutex::new(HashMap::new()));
+    handlers.lock().unwrap().insert(name, handler);
+    Ok(())
+}
+
+#[pyfunction]
+fn submit_task(py: Python, data: &PyAny, priority: Option<i32>, handler: Option<String>) -> PyResult<String> {
+    let state_guard = STATE.lock().unwrap();
+    let state = state_guard.as_ref().ok_or_else(|| {
+        pyo3::exceptions::PyRuntimeError::new_err(
             "Runtime not initialized. Call init_runtime() first."
+        )
+    })?;
+
+    if state.stop_flag.load(Ordering::SeqCst) {
+        return Err(pyo3::exceptions::PyRuntimeError::new_err(
+            "Runtime is shutting down, not accepting new tasks"
         ));
     }
 
-    let config = CONFIG.get().unwrap().lock().unwrap().clone();
-
     {
-        let task_queue = TASK_QUEUE.get().unwrap().lock().unwrap();
-        if task_queue.len() >= config.queue_capacity {
+        let task_queue = state.task_queue.lock().unwrap();
+        if task_queue.len() >= state.config.queue_capacity {
             return Err(pyo3::exceptions::PyRuntimeError::new_err(
                 "Task queue is full, try again later"
             ));
@@ -211,90 +432,104 @@ fn submit_task(py: Python, data: &PyAny, priority: Option<i32>) -> PyResult<Stri
         return Err(pyo3::exceptions::PyTypeError::new_err("Expected bytes or dict"));
     };
 
-    if data_bytes.len() > config.memory_limit_mb * 1024 * 1024 {
+    if data_bytes.len() > state.config.memory_limit_mb * 1024 * 1024 {
         return Err(pyo3::exceptions::PyValueError::new_err(
-            format!("Data size exceeds memory limit of {} MB", config.memory_limit_mb)
+            format!("Data size exceeds memory limit of {} MB", state.config.memory_limit_mb)
         ));
     }
 
     let task_id = Uuid::new_v4();
     let priority_value = priority.unwrap_or(0);
 
+    // The oneshot pair must exist before the task becomes visible to workers
+    // via task_data/task_queue below -- otherwise a worker woken by its own
+    // timer (not the try_send ping) could pop and finish the task before
+    // pending_senders/pending_receivers have an entry for it, leaving the
+    // sender/receiver inserted afterward to leak forever (await_result's
+    // fast path returns straight from `results` without touching them).
+    let (tx, rx) = oneshot::channel::<TaskResult>();
+    state.pending_senders.lock().unwrap().insert(task_id, tx);
+    state.pending_receivers.lock().unwrap().insert(task_id, rx);
+
     {
-        let mut task_data = TASK_DATA.get().unwrap().lock().unwrap();
+        let mut task_data = state.task_data.lock().unwrap();
         task_data.insert(task_id, Task {
             id: task_id,
             data: data_bytes,
             priority: priority_value,
             created_at: Instant::now(),
+            handler,
         });
     }
 
     {
-        let mut task_queue = TASK_QUEUE.get().unwrap().lock().unwrap();
+        let mut task_queue = state.task_queue.lock().unwrap();
         task_queue.push(task_id, -priority_value);
     }
 
-    if let Some(sender) = WORKER_SENDER.get() {
-        let _ = sender.try_send(());
-    }
+    let _ = state.worker_sender.try_send(());
 
     Ok(task_id.to_string())
 }
 
+// Decodes a stored TaskResult into the Python value `get_result`/`await_result`
+// hand back, or raises a RuntimeError carrying the handler's captured error
+// message.
+fn decode_task_result(py: Python, result: &TaskResult) -> PyResult<PyObject> {
+    if let Some(error) = &result.error {
+        return Err(pyo3::exceptions::PyRuntimeError::new_err(error.clone()));
+    }
+
+    if let Ok(json) = from_slice::<Value>(&result.data) {
+        if let Value::Object(map) = json {
+            let py_dict = PyDict::new(py);
+            for (k, v) in map {
+                let py_value = match v {
+                    Value::String(s) => s.into_py(py),
+                    Value::Number(n) => {
+                        if n.is_i64() {
+                            n.as_i64().unwrap().into_py(py)
+                        } else if n.is_u64() {
+                            n.as_u64().unwrap().into_py(py)
+                        } else {
+                            n.as_f64().unwrap().into_py(py)
+                        }
+                    },
+                    Value::Bool(b) => b.into_py(py),
+                    Value::Null => py.None(),
+                    _ => continue,
+                };
+                py_dict.set_item(k, py_value)?;
+            }
+            return Ok(py_dict.into());
+        }
+    }
+
+    Ok(PyBytes::new(py, &result.data).into())
+}
+
 #[pyfunction]
 fn get_result(py: Python, task_id: &str) -> PyResult<Option<PyObject>> {
-    if RUNTIME.get().is_none() {
-        return Err(pyo3::exceptions::PyRuntimeError::new_err(
+    let state_guard = STATE.lock().unwrap();
+    let state = state_guard.as_ref().ok_or_else(|| {
+        pyo3::exceptions::PyRuntimeError::new_err(
             "Runtime not initialized. Call init_runtime() first."
-        ));
-    }
+        )
+    })?;
 
     let uuid = Uuid::parse_str(task_id).map_err(|e| {
         pyo3::exceptions::PyValueError::new_err(format!("Invalid UUID: {}", e))
     })?;
 
-    if let Some(results) = RESULTS.get() {
-        let results_map = results.lock().unwrap();
+    {
+        let results_map = state.results.lock().unwrap();
         if let Some(result) = results_map.get(&uuid) {
-            if let Ok(json) = from_slice::<Value>(&result.data) {
-                let py_dict = PyDict::new(py);
-                match json {
-                    Value::Object(map) => {
-                        for (k, v) in map {
-                            let py_value = match v {
-                                Value::String(s) => s.into_py(py),
-                                Value::Number(n) => {
-                                    if n.is_i64() {
-                                        n.as_i64().unwrap().into_py(py)
-                                    } else if n.is_u64() {
-                                        n.as_u64().unwrap().into_py(py)
-                                    } else {
-                                        n.as_f64().unwrap().into_py(py)
-                                    }
-                                },
-                                Value::Bool(b) => b.into_py(py),
-                                Value::Null => py.None(),
-                                _ => continue,
-                            };
-                            py_dict.set_item(k, py_value)?;
-                        }
-                        return Ok(Some(py_dict.into()));
-                    },
-                    _ => {
-                        let py_bytes = PyBytes::new(py, &result.data);
-                        return Ok(Some(py_bytes.into()));
-                    }
-                }
-            } else {
-                let py_bytes = PyBytes::new(py, &result.data);
-                return Ok(Some(py_bytes.into()));
-            }
+            return decode_task_result(py, result).map(Some);
         }
     }
 
-    if let Some(task_data) = TASK_DATA.get() {
-        let task_map = task_data.lock().unwrap();
+    {
+        let task_map = state.task_data.lock().unwrap();
         if task_map.contains_key(&uuid) {
             return Ok(None);
         }
@@ -304,76 +539,187 @@ fn get_result(py: Python, task_id: &str) -> PyResult<Option<PyObject>> {
 }
 
 #[pyfunction]
-fn get_stats(py: Python) -> PyResult<PyObject> {
-    if RUNTIME.get().is_none() {
-        return Err(pyo3::exceptions::PyRuntimeError::new_err(
+fn await_result(py: Python, task_id: &str) -> PyResult<&PyAny> {
+    let state_guard = STATE.lock().unwrap();
+    let state = state_guard.as_ref().ok_or_else(|| {
+        pyo3::exceptions::PyRuntimeError::new_err(
             "Runtime not initialized. Call init_runtime() first."
-        ));
-    }
+        )
+    })?;
 
-    let dict = PyDict::new(py);
+    let uuid = Uuid::parse_str(task_id).map_err(|e| {
+        pyo3::exceptions::PyValueError::new_err(format!("Invalid UUID: {}", e))
+    })?;
 
-    let queue_size = if let Some(task_queue) = TASK_QUEUE.get() {
-        task_queue.lock().unwrap().len()
-    } else {
-        0
+    // Fast path: the result already landed (the caller awaited late, or is
+    // re-awaiting after a previous await already consumed the receiver).
+    let cached = state.results.lock().unwrap().get(&uuid).cloned();
+    if let Some(result) = cached {
+        return pyo3_asyncio::tokio::future_into_py(py, async move {
+            Python::with_gil(|py| decode_task_result(py, &result))
+        });
+    }
+
+    let receiver = state.pending_receivers.lock().unwrap().remove(&uuid);
+    let receiver = match receiver {
+        Some(rx) => rx,
+        None => {
+            return Err(pyo3::exceptions::PyKeyError::new_err(format!("Task {} not found", task_id)));
+        }
     };
 
-    dict.set_item("queue_size", queue_size)?;
+    pyo3_asyncio::tokio::future_into_py(py, async move {
+        match receiver.await {
+            Ok(result) => Python::with_gil(|py| decode_task_result(py, &result)),
+            Err(_) => Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "task result channel closed unexpectedly"
+            )),
+        }
+    })
+}
 
-    let results_count = if let Some(results) = RESULTS.get() {
-        results.lock().unwrap().len()
-    } else {
-        0
-    };
+#[pyfunction]
+fn submit_and_await(py: Python, data: &PyAny, priority: Option<i32>, handler: Option<String>) -> PyResult<&PyAny> {
+    let task_id = submit_task(py, data, priority, handler)?;
+    await_result(py, &task_id)
+}
 
-    dict.set_item("results_count", results_count)?;
+#[pyfunction]
+fn get_stats(py: Python) -> PyResult<PyObject> {
+    let state_guard = STATE.lock().unwrap();
+    let state = state_guard.as_ref().ok_or_else(|| {
+        pyo3::exceptions::PyRuntimeError::new_err(
+            "Runtime not initialized. Call init_runtime() first."
+        )
+    })?;
 
-    if let Some(config) = CONFIG.get() {
-        let config = config.lock().unwrap();
-        dict.set_item("worker_threads", config.worker_threads)?;
-        dict.set_item("queue_capacity", config.queue_capacity)?;
-        dict.set_item("result_ttl_seconds", config.result_ttl.as_secs())?;
-        dict.set_item("memory_limit_mb", config.memory_limit_mb)?;
-    }
+    let dict = PyDict::new(py);
+
+    dict.set_item("queue_size", state.task_queue.lock().unwrap().len())?;
+    dict.set_item("results_count", state.results.lock().unwrap().len())?;
+    dict.set_item("worker_threads", state.config.worker_threads)?;
+    dict.set_item("queue_capacity", state.config.queue_capacity)?;
+    dict.set_item("result_ttl_seconds", state.config.result_ttl.as_secs())?;
+    dict.set_item("memory_limit_mb", state.config.memory_limit_mb)?;
+    dict.set_item("throttling_ms", state.config.throttling_ms)?;
+    dict.set_item("batch_size", state.config.batch_size)?;
+    dict.set_item("tasks_processed_per_tick", TASKS_PROCESSED_PER_TICK.swap(0, Ordering::Relaxed))?;
+    dict.set_item("tasks_completed", state.tasks_completed.load(Ordering::SeqCst))?;
 
     Ok(dict.into())
 }
 
 #[pyfunction]
 fn clear_all() -> PyResult<()> {
-    if RUNTIME.get().is_none() {
-        return Err(pyo3::exceptions::PyRuntimeError::new_err(
+    let state_guard = STATE.lock().unwrap();
+    let state = state_guard.as_ref().ok_or_else(|| {
+        pyo3::exceptions::PyRuntimeError::new_err(
             "Runtime not initialized. Call init_runtime() first."
-        ));
-    }
-
-    if let Some(task_queue) = TASK_QUEUE.get() {
-        task_queue.lock().unwrap().clear();
-    }
-
-    if let Some(task_data) = TASK_DATA.get() {
-        task_data.lock().unwrap().clear();
-    }
+        )
+    })?;
 
-    if let Some(results) = RESULTS.get() {
-        results.lock().unwrap().clear();
-    }
+    state.task_queue.lock().unwrap().clear();
+    state.task_data.lock().unwrap().clear();
+    state.results.lock().unwrap().clear();
+    state.pending_senders.lock().unwrap().clear();
+    state.pending_receivers.lock().unwrap().clear();
 
     Ok(())
 }
 
+/// Stops accepting new work, stops the worker threads and tears down the
+/// tokio `Runtime`, then clears the state so `init_runtime` can be called
+/// again. With `drain=true`, each worker keeps processing whatever is left
+/// in the queue before exiting; with `drain=false`, workers exit as soon as
+/// they notice the stop flag and anything still queued counts as dropped.
+/// `timeout_ms` bounds how long we wait for worker threads to join — if it
+/// elapses first, the threads are left to finish on their own and the
+/// returned stats reflect what we could observe at that point.
+#[pyfunction]
+fn shutdown(py: Python, timeout_ms: Option<u64>, drain: Option<bool>) -> PyResult<PyObject> {
+    let timeout_ms = timeout_ms.unwrap_or(5000);
+    let drain = drain.unwrap_or(true);
+
+    let state = STATE.lock().unwrap().take().ok_or_else(|| {
+        pyo3::exceptions::PyRuntimeError::new_err(
+            "Runtime not initialized. Call init_runtime() first."
+        )
+    })?;
+
+    let (completed, dropped, joined_cleanly) = py.allow_threads(move || {
+        let RuntimeState {
+            runtime,
+            config,
+            task_queue,
+            task_data,
+            worker_sender,
+            stop_flag,
+            drain_flag,
+            tasks_completed,
+            worker_handles,
+            ..
+        } = state;
+
+        drain_flag.store(drain, Ordering::SeqCst);
+        stop_flag.store(true, Ordering::SeqCst);
+
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+
+        // Wake every parked worker; each ping wakes at most one, so send one
+        // per worker thread. The channel holds a single ping, so wait (up to
+        // the deadline) for each one to be taken instead of dropping it.
+        for _ in 0..config.worker_threads {
+            let _ = worker_sender.send_timeout((), deadline.saturating_duration_since(Instant::now()));
+        }
+
+        let mut joined_cleanly = true;
+        for handle in worker_handles {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                joined_cleanly = false;
+                continue;
+            }
+            // `thread::JoinHandle` has no join-with-timeout, so join it from
+            // a watcher thread and bound our own wait on a channel instead.
+            let (done_tx, done_rx) = bounded::<()>(1);
+            thread::spawn(move || {
+                let _ = handle.join();
+                let _ = done_tx.send(());
+            });
+            if done_rx.recv_timeout(remaining).is_err() {
+                joined_cleanly = false;
+            }
+        }
+
+        let completed = tasks_completed.load(Ordering::SeqCst);
+        let dropped = task_queue.lock().unwrap().len() + task_data.lock().unwrap().len();
+
+        drop(runtime);
+
+        (completed, dropped, joined_cleanly)
+    });
+
+    let dict = PyDict::new(py);
+    dict.set_item("tasks_completed", completed)?;
+    dict.set_item("tasks_dropped", dropped)?;
+    dict.set_item("joined_cleanly", joined_cleanly)?;
+    Ok(dict.into())
+}
 
 #[pymodule]
 fn gilboost(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(init_runtime, m)?)?;
+    m.add_function(wrap_pyfunction!(register_handler, m)?)?;
     m.add_function(wrap_pyfunction!(submit_task, m)?)?;
+    m.add_function(wrap_pyfunction!(submit_and_await, m)?)?;
     m.add_function(wrap_pyfunction!(get_result, m)?)?;
+    m.add_function(wrap_pyfunction!(await_result, m)?)?;
     m.add_function(wrap_pyfunction!(get_stats, m)?)?;
     m.add_function(wrap_pyfunction!(clear_all, m)?)?;
+    m.add_function(wrap_pyfunction!(shutdown, m)?)?;
 
     // Добавляем информацию о версии
     m.add("__version__", "0.1.0")?;
 
     Ok(())
-}
\ No newline at end of file
+}